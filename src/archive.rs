@@ -0,0 +1,480 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Offline archive of rendered posts and the assets they reference, stored
+//! content-addressed on disk so a snapshot keeps working after Medium
+//! deletes or rate-limits the original.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::proxy::build_post;
+use crate::AppData;
+
+const ARCHIVE_ROOT: &str = "archive";
+
+pub mod routes {
+    pub struct Archive {
+        pub archive: &'static str,
+        pub archived: &'static str,
+        pub archived_asset: &'static str,
+        pub index: &'static str,
+        pub gc: &'static str,
+    }
+
+    impl Archive {
+        pub const fn new() -> Self {
+            Self {
+                archive: "/archive/{post}",
+                archived: "/archived/{post}",
+                archived_asset: "/archive/asset/{hash}",
+                index: "/archive",
+                gc: "/archive/gc",
+            }
+        }
+    }
+}
+
+/// A single asset pulled in while archiving a post: the name it was
+/// referenced by on the proxied page, and the content hash it's stored
+/// under.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssetEntry {
+    pub name: String,
+    pub hash: String,
+}
+
+/// Per-post metadata written alongside the archived HTML so the index page
+/// can list archived posts and the GC pass can find unreferenced blobs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub post_id: String,
+    pub username: String,
+    pub slug: String,
+    pub archived_at: i64,
+    pub assets: Vec<AssetEntry>,
+}
+
+/// Content-addressed store for archived posts and their assets.
+///
+/// Layout: `<root>/posts/<post_id>/{post.html,post.json,manifest.json}` and
+/// `<root>/assets/<sha256>{,.ct}`.
+pub struct ArchiveStore {
+    root: PathBuf,
+}
+
+/// Rejects anything that isn't a single, plain path segment, so a
+/// `post_id` sourced from the URL can't escape `<root>/posts` via `/` or
+/// `..`.
+fn is_safe_path_segment(segment: &str) -> bool {
+    !segment.is_empty() && segment != "." && segment != ".." && !segment.contains(['/', '\\'])
+}
+
+/// Rejects anything that isn't a 64-character lowercase hex sha256 digest,
+/// so a `hash` sourced from the URL can't be used to read/write arbitrary
+/// files under `<root>/assets`.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl ArchiveStore {
+    pub fn new() -> Self {
+        Self::at(ARCHIVE_ROOT)
+    }
+
+    pub fn at(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let _ = fs::create_dir_all(root.join("posts"));
+        let _ = fs::create_dir_all(root.join("assets"));
+        Self { root }
+    }
+
+    fn post_dir(&self, post_id: &str) -> io::Result<PathBuf> {
+        if !is_safe_path_segment(post_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid post id: {post_id}"),
+            ));
+        }
+        Ok(self.root.join("posts").join(post_id))
+    }
+
+    fn asset_path(&self, hash: &str) -> io::Result<PathBuf> {
+        if !is_valid_hash(hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid asset hash: {hash}"),
+            ));
+        }
+        Ok(self.root.join("assets").join(hash))
+    }
+
+    /// The sidecar file storing an asset's content type. Takes an
+    /// already-validated hash (via [`Self::asset_path`]) rather than
+    /// re-validating `{hash}.ct` against [`is_valid_hash`].
+    fn asset_ct_path(&self, hash: &str) -> io::Result<PathBuf> {
+        self.asset_path(hash)
+            .map(|path| path.with_extension("ct"))
+    }
+
+    pub fn has_post(&self, post_id: &str) -> bool {
+        self.post_dir(post_id)
+            .map(|dir| dir.join("manifest.json").exists())
+            .unwrap_or(false)
+    }
+
+    /// Writes `bytes` into the asset store under its sha256 hash, deduping
+    /// against an asset that's already there, and returns the hash.
+    pub fn store_asset(&self, bytes: &[u8], content_type: &str) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(bytes);
+        let hash = format!("{:x}", hasher.finalize());
+
+        let path = self.asset_path(&hash)?;
+        if !path.exists() {
+            let tmp = self.root.join("assets").join(format!("{hash}.tmp"));
+            fs::write(&tmp, bytes)?;
+            fs::rename(&tmp, &path)?;
+        }
+        fs::write(self.asset_ct_path(&hash)?, content_type)?;
+        Ok(hash)
+    }
+
+    pub fn load_asset(&self, hash: &str) -> io::Result<(Vec<u8>, String)> {
+        let bytes = fs::read(self.asset_path(hash)?)?;
+        let content_type = fs::read_to_string(self.asset_ct_path(hash)?)
+            .unwrap_or_else(|_| "application/octet-stream".into());
+        Ok((bytes, content_type))
+    }
+
+    pub fn store_post(&self, manifest: &Manifest, html: &str, raw: &str) -> io::Result<()> {
+        let dir = self.post_dir(&manifest.post_id)?;
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("post.html"), html)?;
+        fs::write(dir.join("post.json"), raw)?;
+        let manifest =
+            serde_json::to_vec(manifest).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(dir.join("manifest.json"), manifest)
+    }
+
+    pub fn load_manifest(&self, post_id: &str) -> io::Result<Manifest> {
+        let bytes = fs::read(self.post_dir(post_id)?.join("manifest.json"))?;
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn load_html(&self, post_id: &str) -> io::Result<String> {
+        fs::read_to_string(self.post_dir(post_id)?.join("post.html"))
+    }
+
+    pub fn list_manifests(&self) -> io::Result<Vec<Manifest>> {
+        let mut out = Vec::new();
+        for entry in fs::read_dir(self.root.join("posts"))? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if let Ok(manifest) = self.load_manifest(name) {
+                    out.push(manifest);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Deletes every asset blob not referenced by any manifest, returning
+    /// how many were removed.
+    pub fn gc(&self) -> io::Result<usize> {
+        let referenced: HashSet<String> = self
+            .list_manifests()?
+            .into_iter()
+            .flat_map(|m| m.assets.into_iter().map(|a| a.hash))
+            .collect();
+
+        let mut removed = 0;
+        for entry in fs::read_dir(self.root.join("assets"))? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let name = match file_name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let hash = name.trim_end_matches(".ct");
+            if !referenced.contains(hash) {
+                let _ = fs::remove_file(entry.path());
+                if !name.ends_with(".ct") {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Pulls every `/asset/medium/{name}` reference out of rendered post HTML.
+fn referenced_asset_names(html: &str) -> Vec<String> {
+    const PREFIX: &str = "/asset/medium/";
+    let mut names = Vec::new();
+    let mut rest = html;
+    while let Some(idx) = rest.find(PREFIX) {
+        rest = &rest[idx + PREFIX.len()..];
+        let end = rest
+            .find(|c: char| c == '"' || c == '\'' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        names.push(rest[..end].to_owned());
+    }
+    names
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.archive.archive")]
+async fn archive_post(path: web::Path<String>, data: AppData) -> Result<HttpResponse, Error> {
+    let id = path.split('-').last().ok_or(Error::PostNotFound)?;
+    if !is_safe_path_segment(id) {
+        return Err(Error::PostNotFound);
+    }
+
+    let post = build_post(id, &data).await?;
+    let username = post.data.username.clone();
+    let slug = post.data.slug.clone();
+    let raw = serde_json::to_string(&post.data).unwrap_or_default();
+    let html = post
+        .render_once()
+        .map_err(|e| Error::UnexpectedSchema(e.to_string()))?;
+
+    let store = ArchiveStore::new();
+    // Re-archiving must never lose ground: if this pass fails to fetch an
+    // asset the previous archive already had, keep the previous copy
+    // instead of dropping it from the manifest.
+    let previous_assets: HashMap<String, String> = store
+        .load_manifest(id)
+        .map(|m| m.assets.into_iter().map(|a| (a.name, a.hash)).collect())
+        .unwrap_or_default();
+
+    let mut assets = Vec::new();
+    for name in referenced_asset_names(&html) {
+        let fetched = async {
+            let res = data
+                .client
+                .get(format!("https://miro.medium.com/{name}"))
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .ok()?;
+            let content_type = res
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .to_owned();
+            let bytes = res.bytes().await.ok()?;
+            store.store_asset(&bytes, &content_type).ok()
+        }
+        .await;
+
+        // Medium returned an error (or a deleted asset's 404) and there's
+        // no earlier copy to fall back on; don't memoize that as a real
+        // asset.
+        let hash = match fetched.or_else(|| previous_assets.get(&name).cloned()) {
+            Some(hash) => hash,
+            None => continue,
+        };
+        assets.push(AssetEntry { name, hash });
+    }
+
+    let manifest = Manifest {
+        post_id: id.to_owned(),
+        username,
+        slug,
+        archived_at: Utc::now().timestamp(),
+        assets,
+    };
+
+    store.store_post(&manifest, &html, &raw)?;
+    Ok(HttpResponse::Found()
+        .append_header((
+            actix_web::http::header::LOCATION,
+            crate::V1_API_ROUTES.archive.archived.replace("{post}", id),
+        ))
+        .finish())
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.archive.archived")]
+async fn archived_page(path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let id = path.split('-').last().ok_or(Error::PostNotFound)?;
+    if !is_safe_path_segment(id) {
+        return Err(Error::PostNotFound);
+    }
+
+    let store = ArchiveStore::new();
+    let (manifest, html) = match (store.load_manifest(id), store.load_html(id)) {
+        (Ok(manifest), Ok(html)) => (manifest, html),
+        _ => return Err(Error::PostNotFound),
+    };
+
+    let mut html = html;
+    for asset in &manifest.assets {
+        html = html.replace(
+            &format!("/asset/medium/{}", asset.name),
+            &crate::V1_API_ROUTES
+                .archive
+                .archived_asset
+                .replace("{hash}", &asset.hash),
+        );
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.archive.archived_asset")]
+async fn archived_asset(path: web::Path<String>) -> impl Responder {
+    let store = ArchiveStore::new();
+    match store.load_asset(&path) {
+        Ok((bytes, content_type)) => HttpResponse::Ok().content_type(content_type).body(bytes),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.archive.index")]
+async fn archive_index() -> Result<HttpResponse, Error> {
+    let store = ArchiveStore::new();
+    let manifests = store.list_manifests()?;
+
+    let mut items = String::new();
+    for manifest in &manifests {
+        items.push_str(&format!(
+            r#"<li><a href="{}">{}/{}</a></li>"#,
+            crate::V1_API_ROUTES
+                .archive
+                .archived
+                .replace("{post}", &manifest.post_id),
+            manifest.username,
+            manifest.slug,
+        ));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<html><body><h1>Archived posts</h1><ul>{items}</ul></body></html>"
+        )))
+}
+
+/// Drops asset blobs no archived post references any more. Intended to be
+/// hit by an admin or a periodic job, not end users.
+#[actix_web_codegen_const_routes::post(path = "crate::V1_API_ROUTES.archive.gc")]
+async fn archive_gc() -> Result<HttpResponse, Error> {
+    let store = ArchiveStore::new();
+    let removed = store.gc()?;
+    Ok(HttpResponse::Ok().body(format!("removed {removed} unreferenced asset(s)")))
+}
+
+/// Routes that render HTML and are worth compressing.
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(archive_post);
+    cfg.service(archived_page);
+    cfg.service(archive_index);
+    cfg.service(archive_gc);
+}
+
+/// The archived asset route serves already-compressed image bytes straight
+/// off disk, so it's kept out of the compressed scope.
+pub fn asset_services(cfg: &mut web::ServiceConfig) {
+    cfg.service(archived_asset);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn temp_store() -> ArchiveStore {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        ArchiveStore::at(std::env::temp_dir().join(format!("libmedium-archive-test-{nanos}")))
+    }
+
+    #[test]
+    fn store_asset_dedupes_identical_bytes() {
+        let store = temp_store();
+        let hash_a = store.store_asset(b"hello", "text/plain").unwrap();
+        let hash_b = store.store_asset(b"hello", "text/plain").unwrap();
+        assert_eq!(hash_a, hash_b);
+
+        let (bytes, content_type) = store.load_asset(&hash_a).unwrap();
+        assert_eq!(bytes, b"hello");
+        assert_eq!(content_type, "text/plain");
+    }
+
+    #[test]
+    fn gc_removes_unreferenced_assets_only() {
+        let store = temp_store();
+        let kept = store.store_asset(b"kept", "text/plain").unwrap();
+        let orphan = store.store_asset(b"orphan", "text/plain").unwrap();
+
+        let manifest = Manifest {
+            post_id: "post1".into(),
+            username: "user".into(),
+            slug: "slug".into(),
+            archived_at: 0,
+            assets: vec![AssetEntry {
+                name: "a".into(),
+                hash: kept.clone(),
+            }],
+        };
+        store.store_post(&manifest, "<html></html>", "{}").unwrap();
+
+        let removed = store.gc().unwrap();
+        assert_eq!(removed, 1);
+        assert!(store.load_asset(&kept).is_ok());
+        assert!(store.load_asset(&orphan).is_err());
+    }
+
+    #[test]
+    fn post_dir_rejects_path_traversal_segments() {
+        let store = temp_store();
+        assert!(store.post_dir("normal-post-id").is_ok());
+        assert!(store.post_dir("..").is_err());
+        assert!(store.post_dir("../../etc/passwd").is_err());
+        assert!(store.post_dir("foo/bar").is_err());
+        assert!(store.post_dir("").is_err());
+    }
+
+    #[test]
+    fn asset_path_rejects_anything_that_isnt_a_sha256_hex_digest() {
+        let store = temp_store();
+        let hash = "a".repeat(64);
+        assert!(store.asset_path(&hash).is_ok());
+        assert!(store.asset_path("../../etc/passwd").is_err());
+        assert!(store.asset_path("not-hex").is_err());
+        assert!(store.asset_path(&"a".repeat(63)).is_err());
+        assert!(store.asset_path(&"A".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn load_asset_rejects_invalid_hash_instead_of_touching_the_filesystem() {
+        let store = temp_store();
+        assert!(store.load_asset("../../../etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Disk-backed cache for proxied `miro.medium.com` assets, keyed by a hash
+//! of the asset path, so repeat requests for an image don't round-trip to
+//! Medium.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use filetime::{set_file_mtime, FileTime};
+use sha2::{Digest, Sha256};
+
+const DEFAULT_ROOT: &str = "asset_cache";
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// A cached asset: its bytes, the `Content-Type` it was served with, and an
+/// `ETag` derived from the content so browsers can send `If-None-Match`.
+pub struct CachedAsset {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+    pub etag: String,
+}
+
+pub struct AssetCache {
+    root: PathBuf,
+    max_bytes: u64,
+}
+
+impl AssetCache {
+    /// Builds the cache from `ASSET_CACHE_DIR`/`ASSET_CACHE_MAX_BYTES`
+    /// (falling back to the defaults above when unset), so the directory
+    /// and size are actually configurable at deploy time.
+    pub fn new() -> Self {
+        let root = std::env::var("ASSET_CACHE_DIR").unwrap_or_else(|_| DEFAULT_ROOT.to_owned());
+        let max_bytes = std::env::var("ASSET_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        Self::with_capacity(root, max_bytes)
+    }
+
+    pub fn with_capacity(root: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        let root = root.into();
+        let _ = fs::create_dir_all(&root);
+        Self { root, max_bytes }
+    }
+
+    /// Cache entries are keyed by the *asset path* (not its bytes), so the
+    /// same path always lands on the same file regardless of what Medium
+    /// happens to return for it.
+    fn key_hash(key: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(key.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn bin_path(&self, key: &str) -> PathBuf {
+        self.root.join(format!("{}.bin", Self::key_hash(key)))
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.root.join(format!("{}.meta", Self::key_hash(key)))
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedAsset> {
+        let bin_path = self.bin_path(key);
+        let bytes = fs::read(&bin_path).ok()?;
+        let meta = fs::read_to_string(self.meta_path(key)).ok()?;
+        let (content_type, etag) = meta.split_once('\n')?;
+
+        // Approximates LRU recency: bump mtime on every hit so eviction can
+        // sort by "last used" rather than "first written", without
+        // rewriting the blob itself (that would race with a concurrent
+        // reader the way `put()`'s temp-file-then-rename is careful not to).
+        let _ = set_file_mtime(&bin_path, FileTime::now());
+
+        Some(CachedAsset {
+            bytes,
+            content_type: content_type.to_owned(),
+            etag: etag.to_owned(),
+        })
+    }
+
+    /// Atomically stores `bytes` under `key`: write to a temp file, then
+    /// rename into place, so a concurrent reader never observes a
+    /// partially-written entry.
+    pub fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(bytes);
+        let etag = format!("\"{:x}\"", hasher.finalize());
+
+        let bin_path = self.bin_path(key);
+        let tmp_path = self.root.join(format!("{}.tmp", Self::key_hash(key)));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &bin_path)?;
+        let meta_tmp_path = self.root.join(format!("{}.meta.tmp", Self::key_hash(key)));
+        fs::write(&meta_tmp_path, format!("{content_type}\n{etag}"))?;
+        fs::rename(&meta_tmp_path, self.meta_path(key))?;
+
+        self.evict_if_over_capacity()?;
+        Ok(etag)
+    }
+
+    /// Simple LRU eviction: while the cache is over `max_bytes`, drop the
+    /// least recently touched entry.
+    fn evict_if_over_capacity(&self) -> io::Result<()> {
+        let mut entries: Vec<(PathBuf, SystemTime, u64)> = Vec::new();
+        let mut total = 0u64;
+
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total += metadata.len();
+            if entry.path().extension().and_then(|e| e.to_str()) == Some("bin") {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((entry.path(), modified, metadata.len()));
+            }
+        }
+
+        if total <= self.max_bytes {
+            return Ok(());
+        }
+
+        entries.sort_by_key(|(_, modified, _)| *modified);
+        for (bin_path, _, size) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            let meta_path = bin_path.with_extension("meta");
+            let meta_size = fs::metadata(&meta_path).map(|m| m.len()).unwrap_or(0);
+            let _ = fs::remove_file(&meta_path);
+            fs::remove_file(&bin_path)?;
+            total = total.saturating_sub(size + meta_size);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Embed resolution for `IFRAME` paragraphs that aren't GitHub gists.
+//!
+//! `page()` only knew how to inline gists; every other embed (YouTube,
+//! Twitter, CodePen, ...) rendered blank. This gives every provider its own
+//! [`EmbedResolver`], dispatched by matching the iframe's href, with a
+//! generic oEmbed fallback for anything unrecognized.
+use std::future::Future;
+use std::pin::Pin;
+
+use serde::Deserialize;
+
+type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Option<String>> + Send + 'a>>;
+
+/// A provider-specific strategy for turning an iframe href into inline
+/// HTML, so the rendered post doesn't depend on third-party JS loading.
+pub trait EmbedResolver: Send + Sync {
+    /// Whether this resolver knows how to handle `href`.
+    fn matches(&self, href: &str) -> bool;
+
+    /// Resolve `href` to the HTML that should replace the iframe.
+    fn resolve<'a>(&'a self, href: &'a str, client: &'a reqwest::Client) -> ResolveFuture<'a>;
+}
+
+struct YouTubeResolver;
+
+impl EmbedResolver for YouTubeResolver {
+    fn matches(&self, href: &str) -> bool {
+        href.contains("youtube.com") || href.contains("youtu.be") || href.contains("vimeo.com")
+    }
+
+    fn resolve<'a>(&'a self, href: &'a str, _client: &'a reqwest::Client) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let src = if href.contains("vimeo.com") {
+                href.replacen("vimeo.com", "player.vimeo.com/video", 1)
+            } else {
+                href.replacen("youtu.be/", "www.youtube-nocookie.com/embed/", 1)
+                    .replacen("youtube.com", "www.youtube-nocookie.com", 1)
+            };
+            Some(format!(
+                r#"<iframe src="{src}" loading="lazy" allowfullscreen referrerpolicy="no-referrer"></iframe>"#
+            ))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct OembedResp {
+    html: Option<String>,
+    #[serde(default)]
+    thumbnail_url: Option<String>,
+}
+
+struct TwitterResolver;
+
+impl EmbedResolver for TwitterResolver {
+    fn matches(&self, href: &str) -> bool {
+        href.contains("twitter.com") || href.contains("x.com")
+    }
+
+    fn resolve<'a>(&'a self, href: &'a str, client: &'a reqwest::Client) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let url = format!(
+                "https://publish.twitter.com/oembed?url={}&omit_script=true",
+                urlencoding::encode(href)
+            );
+            let resp = client.get(url).send().await.ok()?;
+            let oembed: OembedResp = resp.json().await.ok()?;
+            // `omit_script=true` already drops the widgets.js tag; this is a
+            // belt-and-braces strip in case a provider ignores the param.
+            oembed.html.map(|html| strip_script_tags(&html))
+        })
+    }
+}
+
+struct GenericOembedResolver;
+
+impl EmbedResolver for GenericOembedResolver {
+    fn matches(&self, _href: &str) -> bool {
+        true
+    }
+
+    fn resolve<'a>(&'a self, href: &'a str, client: &'a reqwest::Client) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let origin = href.splitn(4, '/').take(3).collect::<Vec<_>>().join("/");
+            let url = format!(
+                "{origin}/oembed?url={}&format=json",
+                urlencoding::encode(href)
+            );
+            let resp = client.get(url).send().await.ok()?;
+            let oembed: OembedResp = resp.json().await.ok()?;
+            oembed.html.or_else(|| {
+                oembed
+                    .thumbnail_url
+                    .map(|src| format!(r#"<img src="{src}" loading="lazy">"#))
+            })
+        })
+    }
+}
+
+fn strip_script_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<script") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start..].find("</script>") {
+            Some(end) => &rest[start + end + "</script>".len()..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+fn resolvers() -> Vec<Box<dyn EmbedResolver>> {
+    vec![
+        Box::new(YouTubeResolver),
+        Box::new(TwitterResolver),
+        Box::new(GenericOembedResolver),
+    ]
+}
+
+/// Resolves a non-gist `IFRAME` href to inline HTML via the first matching
+/// [`EmbedResolver`] (CodePen and anything else fall through to the generic
+/// oEmbed resolver).
+pub async fn resolve_embed(href: &str, client: &reqwest::Client) -> Option<String> {
+    for resolver in resolvers() {
+        if resolver.matches(href) {
+            return resolver.resolve(href, client).await;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn youtube_resolver_matches_youtube_and_vimeo_hosts() {
+        let resolver = YouTubeResolver;
+        assert!(resolver.matches("https://www.youtube.com/embed/abc"));
+        assert!(resolver.matches("https://youtu.be/abc"));
+        assert!(resolver.matches("https://vimeo.com/123"));
+        assert!(!resolver.matches("https://codepen.io/foo/pen/bar"));
+    }
+
+    #[test]
+    fn twitter_resolver_matches_twitter_and_x_hosts() {
+        let resolver = TwitterResolver;
+        assert!(resolver.matches("https://twitter.com/user/status/1"));
+        assert!(resolver.matches("https://x.com/user/status/1"));
+        assert!(!resolver.matches("https://youtube.com/watch?v=1"));
+    }
+
+    #[test]
+    fn generic_oembed_resolver_is_the_fallback() {
+        assert!(GenericOembedResolver.matches("https://codepen.io/foo/pen/bar"));
+    }
+
+    #[test]
+    fn resolvers_dispatch_by_href_with_generic_as_last_resort() {
+        let all = resolvers();
+        assert!(all.iter().any(|r| r.matches("https://youtu.be/abc")));
+        assert!(all.iter().any(|r| r.matches("https://twitter.com/x/status/1")));
+        // Nothing but the fallback should claim an unrelated provider.
+        let matching: Vec<_> = all
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.matches("https://codepen.io/foo/pen/bar"))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(matching, vec![all.len() - 1]);
+    }
+
+    #[test]
+    fn strip_script_tags_removes_script_elements() {
+        let html = r#"<blockquote>tweet</blockquote><script src="widgets.js"></script>"#;
+        assert_eq!(strip_script_tags(html), "<blockquote>tweet</blockquote>");
+    }
+}
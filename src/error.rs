@@ -0,0 +1,76 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Crate-wide error type so a malformed upstream response or a post
+//! missing an optional field turns into a proper HTTP error response
+//! instead of panicking a worker.
+use std::fmt;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request to Medium failed.
+    UpstreamFetch(String),
+    /// No post could be resolved from the given id/slug.
+    PostNotFound,
+    /// Medium returned something that doesn't match the shape we expect.
+    UnexpectedSchema(String),
+    /// Reading or writing the local asset/archive cache failed.
+    Storage(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UpstreamFetch(msg) => write!(f, "upstream fetch failed: {msg}"),
+            Error::PostNotFound => write!(f, "post not found"),
+            Error::UnexpectedSchema(msg) => write!(f, "unexpected response from Medium: {msg}"),
+            Error::Storage(_) => write!(f, "local storage error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::UpstreamFetch(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        log::error!("local storage error: {e}");
+        Error::Storage(e.to_string())
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::UpstreamFetch(_) | Error::UnexpectedSchema(_) => StatusCode::BAD_GATEWAY,
+            Error::PostNotFound => StatusCode::NOT_FOUND,
+            Error::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
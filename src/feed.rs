@@ -0,0 +1,231 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! RSS/Atom feed of an author's recent posts, so they can be followed from
+//! a feed reader through the proxy instead of Medium directly.
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
+use chrono::{TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::AppData;
+
+const FEED_LEN: usize = 20;
+
+pub mod routes {
+    pub struct Feed {
+        pub feed: &'static str,
+    }
+
+    impl Feed {
+        pub const fn new() -> Self {
+            Self {
+                feed: "/{username}/feed",
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    format: Option<String>,
+}
+
+fn wants_atom(req: &HttpRequest, query: &FeedQuery) -> bool {
+    if let Some(format) = &query.format {
+        return format.eq_ignore_ascii_case("atom");
+    }
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|accept| accept.contains("atom"))
+        .unwrap_or(false)
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct Entry {
+    title: String,
+    link: String,
+    date: chrono::DateTime<Utc>,
+    preview_image: Option<String>,
+}
+
+async fn collect_entries(username: &str, origin: &str, data: &AppData) -> Vec<Entry> {
+    let post_ids = data.get_user_posts(username).await;
+    let mut entries = Vec::with_capacity(post_ids.len().min(FEED_LEN));
+
+    for id in post_ids.into_iter().take(FEED_LEN) {
+        let post = data.get_post_light(&id).await;
+        let link = format!(
+            "{origin}{}",
+            crate::V1_API_ROUTES.proxy.get_page(&post.username, &post.slug)
+        );
+        let preview_image = post
+            .preview_image
+            .as_ref()
+            .and_then(|img| img.id.as_ref())
+            .map(|id| format!("{origin}{}", crate::V1_API_ROUTES.proxy.get_medium_asset(id)));
+
+        entries.push(Entry {
+            title: post.title,
+            link,
+            date: Utc.timestamp_millis(post.created_at),
+            preview_image,
+        });
+    }
+    entries
+}
+
+fn render_rss(username: &str, origin: &str, entries: &[Entry]) -> String {
+    let mut items = String::new();
+    for entry in entries {
+        items.push_str("<item>");
+        items.push_str(&format!("<title>{}</title>", escape_xml(&entry.title)));
+        items.push_str(&format!("<link>{}</link>", escape_xml(&entry.link)));
+        items.push_str(&format!("<guid>{}</guid>", escape_xml(&entry.link)));
+        items.push_str(&format!(
+            "<pubDate>{}</pubDate>",
+            entry.date.to_rfc2822()
+        ));
+        if let Some(preview) = &entry.preview_image {
+            items.push_str(&format!(
+                r#"<enclosure url="{}" type="image/jpeg"/>"#,
+                escape_xml(preview)
+            ));
+        }
+        items.push_str("</item>");
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0"><channel><title>{username} on Medium</title><link>{origin}</link><description>Recent posts by {username}</description>{items}</channel></rss>"#,
+        username = escape_xml(username),
+        origin = escape_xml(origin),
+        items = items
+    )
+}
+
+fn render_atom(username: &str, origin: &str, entries: &[Entry]) -> String {
+    let mut items = String::new();
+    for entry in entries {
+        items.push_str("<entry>");
+        items.push_str(&format!("<title>{}</title>", escape_xml(&entry.title)));
+        items.push_str(&format!(
+            r#"<link href="{}"/>"#,
+            escape_xml(&entry.link)
+        ));
+        items.push_str(&format!("<id>{}</id>", escape_xml(&entry.link)));
+        items.push_str(&format!(
+            "<updated>{}</updated>",
+            entry.date.to_rfc3339()
+        ));
+        items.push_str("</entry>");
+    }
+
+    // RFC 4287 requires `updated` on the feed itself, not just its entries.
+    let updated = entries
+        .iter()
+        .map(|entry| entry.date)
+        .max()
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>{username} on Medium</title><id>{origin}/{username}/feed</id><updated>{updated}</updated>{items}</feed>"#,
+        username = escape_xml(username),
+        origin = escape_xml(origin),
+        updated = updated,
+        items = items
+    )
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.feed.feed")]
+async fn feed(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<FeedQuery>,
+    data: AppData,
+) -> impl Responder {
+    let username = path.into_inner();
+    let conn = req.connection_info().clone();
+    let origin = format!("{}://{}", conn.scheme(), conn.host());
+
+    let entries = collect_entries(&username, &origin, &data).await;
+
+    if wants_atom(&req, &query) {
+        HttpResponse::Ok()
+            .content_type("application/atom+xml; charset=utf-8")
+            .body(render_atom(&username, &origin, &entries))
+    } else {
+        HttpResponse::Ok()
+            .content_type("application/rss+xml; charset=utf-8")
+            .body(render_rss(&username, &origin, &entries))
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(feed);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn sample_entries() -> Vec<Entry> {
+        vec![Entry {
+            title: "Tom & Jerry <says> \"hi\"".into(),
+            link: "https://example.com/@user/post-abc".into(),
+            date: Utc.timestamp_millis(1_700_000_000_000),
+            preview_image: Some("https://example.com/asset/medium/1*abc.png".into()),
+        }]
+    }
+
+    #[test]
+    fn escape_xml_escapes_all_reserved_characters() {
+        assert_eq!(
+            escape_xml("Tom & Jerry <says> \"hi\""),
+            "Tom &amp; Jerry &lt;says&gt; &quot;hi&quot;"
+        );
+    }
+
+    #[test]
+    fn render_rss_escapes_entry_titles_and_includes_enclosure() {
+        let xml = render_rss("user", "https://example.com", &sample_entries());
+        assert!(xml.contains("Tom &amp; Jerry &lt;says&gt; &quot;hi&quot;"));
+        assert!(xml.contains(r#"<enclosure url="https://example.com/asset/medium/1*abc.png""#));
+    }
+
+    #[test]
+    fn render_atom_includes_feed_level_updated() {
+        let xml = render_atom("user", "https://example.com", &sample_entries());
+        // One <updated> for the feed itself, one for the single entry.
+        assert_eq!(xml.matches("<updated>").count(), 2);
+    }
+
+    #[test]
+    fn render_atom_with_no_entries_still_has_feed_level_updated() {
+        let xml = render_atom("user", "https://example.com", &[]);
+        assert_eq!(xml.matches("<updated>").count(), 1);
+    }
+}
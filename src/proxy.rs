@@ -16,13 +16,14 @@
  */
 use std::ops::{Bound, RangeBounds};
 
-use actix_web::{http::header, web, HttpResponse, Responder};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
 use chrono::{TimeZone, Utc};
 use futures::future::join_all;
 use reqwest::header::CONTENT_TYPE;
 use sailfish::TemplateOnce;
 
 use crate::data::PostResp;
+use crate::error::Error;
 use crate::post::apply_markup;
 use crate::AppData;
 
@@ -137,23 +138,56 @@ async fn index() -> impl Responder {
 }
 
 #[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.asset")]
-async fn assets(path: web::Path<String>, data: AppData) -> impl Responder {
-    let res = data
-        .client
-        .get(format!("https://miro.medium.com/{}", path))
-        .send()
-        .await
-        .unwrap();
-    let headers = res.headers();
-    let content_type = headers.get(CONTENT_TYPE).unwrap();
-    HttpResponse::Ok()
+async fn assets(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: AppData,
+) -> Result<HttpResponse, Error> {
+    let cache = crate::cache::AssetCache::new();
+
+    let asset = match cache.get(&path) {
+        Some(asset) => asset,
+        None => {
+            let res = data
+                .client
+                .get(format!("https://miro.medium.com/{}", path))
+                .send()
+                .await?
+                .error_for_status()?;
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .to_owned();
+            let bytes = res.bytes().await?;
+            let etag = cache.put(&path, &bytes, &content_type)?;
+            crate::cache::CachedAsset {
+                bytes: bytes.to_vec(),
+                content_type,
+                etag,
+            }
+        }
+    };
+
+    if req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        == Some(asset.etag.as_str())
+    {
+        return Ok(HttpResponse::NotModified().finish());
+    }
+
+    Ok(HttpResponse::Ok()
         .insert_header(header::CacheControl(vec![
             header::CacheDirective::Public,
             header::CacheDirective::Extension("immutable".into(), None),
             header::CacheDirective::MaxAge(CACHE_AGE),
         ]))
-        .content_type(content_type)
-        .body(res.bytes().await.unwrap())
+        .insert_header((header::ETAG, asset.etag))
+        .content_type(asset.content_type)
+        .body(asset.bytes))
 }
 
 #[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.by_post_id")]
@@ -170,47 +204,49 @@ async fn by_post_id(path: web::Path<String>, data: AppData) -> impl Responder {
 }
 
 #[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.top_level_post")]
-async fn by_top_level_post(path: web::Path<String>, data: AppData) -> impl Responder {
-    if let Some(post_id) = path.split('-').last() {
-        let post_data = data.get_post_light(post_id).await;
-        HttpResponse::Found()
-            .append_header((
-                header::LOCATION,
-                crate::V1_API_ROUTES
-                    .proxy
-                    .get_page(&post_data.username, &post_data.slug),
-            ))
-            .finish()
-    } else {
-        HttpResponse::NotFound().body("Post not found, please file bug report")
-    }
+async fn by_top_level_post(path: web::Path<String>, data: AppData) -> Result<HttpResponse, Error> {
+    let post_id = path.split('-').last().ok_or(Error::PostNotFound)?;
+    let post_data = data.get_post_light(post_id).await;
+    Ok(HttpResponse::Found()
+        .append_header((
+            header::LOCATION,
+            crate::V1_API_ROUTES
+                .proxy
+                .get_page(&post_data.username, &post_data.slug),
+        ))
+        .finish())
 }
 
-#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.page")]
-async fn page(path: web::Path<(String, String)>, data: AppData) -> impl Responder {
-    let post_id = path.1.split('-').last();
-    if post_id.is_none() {
-        return HttpResponse::BadRequest().finish();
-    }
-    let id = post_id.unwrap();
-
+/// Fetches and assembles the [`Post`] template for `id`, resolving gists and
+/// every other piece `page()` needs to render. Pulled out of the `page`
+/// handler so other consumers (e.g. the archiver) can reuse it.
+///
+/// Posts missing optional fields (no preview image, an iframe with no
+/// resolvable resource) degrade gracefully rather than erroring: they just
+/// render without that element.
+pub(crate) async fn build_post(id: &str, data: &AppData) -> Result<Post, Error> {
     let post_data = data.get_post(id).await;
     let mut futs = Vec::new();
+    let mut embed_targets: Vec<(usize, String)> = Vec::new();
     let paragraphs = &post_data.content.body_model.paragraphs;
 
-    for p in paragraphs.iter() {
+    for (idx, p) in paragraphs.iter().enumerate() {
         if p.type_ == "IFRAME" {
-            let src = &p
+            let src = p
                 .iframe
                 .as_ref()
-                .unwrap()
-                .media_resource
-                .as_ref()
-                .unwrap()
-                .href;
+                .and_then(|iframe| iframe.media_resource.as_ref())
+                .map(|resource| resource.href.clone());
+            let src = match src {
+                Some(src) => src,
+                // Malformed iframe paragraph: render the post without it
+                // rather than aborting.
+                None => continue,
+            };
             if src.contains("gist.github.com") {
-                let fut = data.get_gist(src.to_owned());
-                futs.push(fut);
+                futs.push(data.get_gist(src));
+            } else {
+                embed_targets.push((idx, src));
             }
         }
     }
@@ -229,15 +265,26 @@ async fn page(path: web::Path<(String, String)>, data: AppData) -> impl Responde
     let preview_img = post_data
         .preview_image
         .as_ref()
-        .unwrap()
-        .id
-        .as_ref()
-        .unwrap();
-    let preview_img = crate::V1_API_ROUTES.proxy.get_medium_asset(preview_img);
-
-    let paragraphs = apply_markup(&post_data, &gists);
+        .and_then(|img| img.id.as_ref())
+        .map(|id| crate::V1_API_ROUTES.proxy.get_medium_asset(id))
+        .unwrap_or_default();
+
+    let mut paragraphs = apply_markup(&post_data, &gists);
+    if !embed_targets.is_empty() {
+        let resolved = join_all(
+            embed_targets
+                .iter()
+                .map(|(_, href)| crate::embed::resolve_embed(href, &data.client)),
+        )
+        .await;
+        for ((idx, _), html) in embed_targets.iter().zip(resolved) {
+            if let (Some(html), Some(slot)) = (html, paragraphs.get_mut(*idx)) {
+                *slot = html;
+            }
+        }
+    }
 
-    let page = Post {
+    Ok(Post {
         id: id.to_owned(),
         data: post_data,
         date,
@@ -245,20 +292,45 @@ async fn page(path: web::Path<(String, String)>, data: AppData) -> impl Responde
         reading_time,
         preview_img,
         paragraphs,
-    };
+    })
+}
 
-    let page = page.render_once().unwrap();
-    HttpResponse::Ok()
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.page")]
+async fn page(path: web::Path<(String, String)>, data: AppData) -> Result<HttpResponse, Error> {
+    let id = path.1.split('-').last().ok_or(Error::PostNotFound)?;
+
+    let post = build_post(id, &data).await?;
+    let page = post
+        .render_once()
+        .map_err(|e| Error::UnexpectedSchema(e.to_string()))?;
+    Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(page)
+        .body(page))
 }
 
+// Rendered HTML and feed XML compress well; already-compressed image bytes
+// served by `assets()`/`archived_asset()` don't, so those stay outside this
+// scope and go out over the wire untouched.
 pub fn services(cfg: &mut web::ServiceConfig) {
-    cfg.service(by_post_id);
     cfg.service(assets);
-    cfg.service(page);
-    cfg.service(by_top_level_post);
-    cfg.service(index);
+    crate::archive::asset_services(cfg);
+
+    cfg.service(
+        web::scope("")
+            .wrap(actix_web::middleware::Compress::default())
+            // Static-prefix routes MUST be registered before the
+            // single/double dynamic-segment catch-alls below: actix-web
+            // matches overlapping path patterns in registration order, not
+            // by specificity, so `by_top_level_post`/`page` would otherwise
+            // swallow `/archive/...`, `/archived/...` and `/{username}/feed`
+            // by treating "archive"/"archived" as a post id or username.
+            .configure(crate::archive::services)
+            .configure(crate::feed::services)
+            .service(by_post_id)
+            .service(page)
+            .service(by_top_level_post)
+            .service(index),
+    );
 }
 
 #[cfg(test)]
@@ -311,4 +383,57 @@ mod tests {
             assert!(res.contains(include_str!("../tests/7158b1cdd50c.html")));
         }
     }
+
+    /// `/archive` and `/archived/{post}` overlap with the catch-all
+    /// `by_top_level_post`/`page` routes on segment count; this exercises
+    /// the real `services()` routing table (not just the individual
+    /// handlers) to make sure the static-prefix archive routes win.
+    #[actix_rt::test]
+    async fn archive_routes_are_not_shadowed_by_catch_all_routes() {
+        let data = Data::new();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
+
+        // `by_top_level_post` would treat "archive" as a post id and 302 to
+        // a lookup of a nonexistent post; the real `archive_index` handler
+        // answers locally and always returns 200.
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/archive").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // `page` would treat "archived" as a username and try to fetch a
+        // Medium post for whatever id follows; the real `archived_page`
+        // handler 404s on an unarchived post instead of attempting that.
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/archived/not-archived-yet")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// `/{username}/feed` overlaps with `page`'s `/{username}/{post}` on
+    /// segment count; this exercises the real `services()` routing table to
+    /// make sure `feed` wins instead of `page` trying (and failing) to
+    /// fetch a post literally named "feed".
+    #[actix_rt::test]
+    async fn feed_route_is_not_shadowed_by_page_route() {
+        let data = Data::new();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/ftrain/feed").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let content_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        assert!(content_type.contains("rss+xml") || content_type.contains("atom+xml"));
+    }
 }